@@ -1,5 +1,11 @@
+use axum::extract::State;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
 use bytes::Bytes;
 use dotenv::dotenv;
+use futures::stream::{self, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
 use std::future::Future;
 use std::pin::Pin;
 use std::string::String;
@@ -9,7 +15,305 @@ use teloxide::RequestError;
 use teloxide::{prelude::*, types::InputFile};
 use thirtyfour::WebDriver;
 use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, Mutex};
+
+/// Operator-tunable settings loaded from `blog-to-speech.toml` via `confy`.
+///
+/// Any field missing from the file falls back to the value in [`Config::default`],
+/// which reproduces the behaviour the bot shipped with before it was
+/// configurable. The struct is passed around behind an `Arc` so the async tasks
+/// can share it cheaply.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+struct Config {
+    /// Chat-completions model used to clean up the scraped post.
+    edit_model: String,
+    /// Text-to-speech model used to synthesize the audio.
+    tts_model: String,
+    /// Voice passed to the TTS endpoint.
+    voice: String,
+    /// Instruction given to the edit model before the post is supplied.
+    edit_prompt: String,
+    /// Maximum characters per chunk handed to the TTS endpoint.
+    max_chunk_chars: usize,
+    /// Path to the `geckodriver` binary to launch.
+    geckodriver_path: String,
+    /// Number of headless Firefox sessions kept warm in the pool. Each
+    /// concurrent request borrows one, so this caps how many scrapes run at once.
+    ///
+    /// A single geckodriver proxies exactly one Marionette session at a time, so
+    /// the default is `1`; values above `1` require one geckodriver instance per
+    /// session (not managed here) and will otherwise fail to initialize.
+    driver_pool_size: usize,
+    /// How many chunk TTS conversions may be in flight at the same time. Keeps
+    /// us under OpenAI's rate limits while still parallelizing long posts.
+    max_tts_concurrency: usize,
+    /// How many times a [`Recoverable`](PipelineError::Recoverable) failure is
+    /// retried before giving up.
+    max_retries: usize,
+    /// Base delay in milliseconds for the exponential backoff between retries.
+    retry_base_delay_ms: u64,
+    /// Telegram user IDs permitted to drive the bot. An empty list leaves the
+    /// bot open to everyone, matching its original behaviour.
+    allowed_user_ids: Vec<u64>,
+    /// Whether to start the optional local HTTP API. Off by default so the bot
+    /// never fights for a port unless an operator opts in.
+    http_enabled: bool,
+    /// Address the local HTTP API binds to when enabled.
+    http_addr: String,
+    /// Optional bearer token required on `POST /speech`. When unset the endpoint
+    /// is unauthenticated, which is only safe on a loopback `http_addr`.
+    http_auth_token: Option<String>,
+    /// Stitch the per-chunk audio into a single file and upload it once instead
+    /// of sending each part as a separate attachment.
+    single_file_upload: bool,
+    /// Optional path to a small silent MP3 frame inserted between parts when
+    /// `single_file_upload` is set, to give a short pause between chunks.
+    silence_frame_path: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            edit_model: "gpt-4o".to_string(),
+            tts_model: "tts-1".to_string(),
+            voice: "nova".to_string(),
+            edit_prompt: "Given text from a blog post:\n- Remove any introductory statement or metadata\n- Redact code blocks and replace them with a short technical explanation of their content. Start with \"EDIT:\". End with \"END OF EDIT.\".\nEmojis or other characters that cannot be pronounced should be removed.\nYour response will be directly read of the user - so avoid any additional content besides the edited post\n\nOK?".to_string(),
+            max_chunk_chars: 4096,
+            geckodriver_path: "geckodriver".to_string(),
+            driver_pool_size: 1,
+            max_tts_concurrency: 4,
+            max_retries: 3,
+            retry_base_delay_ms: 500,
+            allowed_user_ids: Vec::new(),
+            http_enabled: false,
+            http_addr: "127.0.0.1:8000".to_string(),
+            http_auth_token: None,
+            single_file_upload: false,
+            silence_frame_path: None,
+        }
+    }
+}
+
+/// Derive a download-friendly file name for the whole post from its URL, e.g.
+/// `https://example.com/posts/my-post/` → `my-post.mp3`.
+fn post_file_name(url: &str) -> String {
+    let name = url
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .filter(|segment| !segment.is_empty())
+        .unwrap_or("post");
+    format!("{name}.mp3")
+}
+
+/// Whether `user` is permitted to use the bot. An empty allowlist permits
+/// everyone; otherwise the sender must be present and listed.
+fn is_authorized(user: Option<&teloxide::types::User>, config: &Config) -> bool {
+    if config.allowed_user_ids.is_empty() {
+        return true;
+    }
+    match user {
+        Some(user) => config.allowed_user_ids.contains(&user.id.0),
+        None => false,
+    }
+}
+
+/// A failure split by whether retrying it could plausibly succeed.
+///
+/// `Recoverable` covers transient trouble — network timeouts, HTTP 429/5xx,
+/// stale geckodriver sessions — that a retry with backoff can clear.
+/// `Fatal` covers permanent problems — auth errors, malformed URLs, missing
+/// page structure — where retrying would only waste time, so they are surfaced
+/// immediately through the existing `handle_error` path.
+#[derive(Debug)]
+enum PipelineError {
+    Recoverable(Box<dyn Error + Send + Sync>),
+    Fatal(Box<dyn Error + Send + Sync>),
+}
+
+impl std::fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PipelineError::Recoverable(e) => write!(f, "recoverable: {e}"),
+            PipelineError::Fatal(e) => write!(f, "fatal: {e}"),
+        }
+    }
+}
+
+impl Error for PipelineError {}
+
+/// Classify a `reqwest` transport error. Timeouts and connection drops are
+/// transient; anything else (e.g. a malformed request) is treated as fatal.
+fn classify_reqwest(e: reqwest::Error) -> PipelineError {
+    if e.is_timeout() || e.is_connect() || e.is_request() {
+        PipelineError::Recoverable(Box::new(e))
+    } else {
+        PipelineError::Fatal(Box::new(e))
+    }
+}
+
+/// Classify a non-success HTTP status. 429 and 5xx are worth retrying; the rest
+/// (notably 401/403 auth failures and 4xx) are fatal.
+fn classify_status(status: reqwest::StatusCode, message: String) -> PipelineError {
+    let err: Box<dyn Error + Send + Sync> =
+        Box::new(std::io::Error::new(std::io::ErrorKind::Other, message));
+    if status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+        PipelineError::Recoverable(err)
+    } else {
+        PipelineError::Fatal(err)
+    }
+}
+
+/// Classify a WebDriver error. A missing element (such as the `<article>`)
+/// means the page isn't a recognisable post (fatal); everything else — dropped sessions, timeouts —
+/// is a transient geckodriver hiccup worth retrying.
+fn classify_webdriver(e: thirtyfour::error::WebDriverError) -> PipelineError {
+    let fatal = matches!(e, thirtyfour::error::WebDriverError::NoSuchElement(_));
+    if fatal {
+        PipelineError::Fatal(Box::new(e))
+    } else {
+        PipelineError::Recoverable(Box::new(e))
+    }
+}
+
+/// Exponential backoff with jitter: `base * 2^attempt` plus a random slice of
+/// `base`, so concurrent retries don't stampede in lockstep.
+fn backoff_delay(base_ms: u64, attempt: usize) -> u64 {
+    let factor = 1u64 << attempt.min(10);
+    let exp = base_ms.saturating_mul(factor);
+    let jitter = rand::random::<u64>() % base_ms.max(1);
+    exp.saturating_add(jitter)
+}
+
+/// Run `op`, retrying `Recoverable` failures with exponential backoff up to
+/// `config.max_retries` times. `Fatal` failures return on the first attempt.
+async fn retry_recoverable<F, Fut, T>(
+    config: &Config,
+    mut op: F,
+) -> Result<T, Box<dyn Error + Send + Sync>>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, PipelineError>>,
+{
+    let mut attempt = 0;
+    loop {
+        match op().await {
+            Ok(value) => return Ok(value),
+            Err(PipelineError::Fatal(e)) => return Err(e),
+            Err(PipelineError::Recoverable(e)) => {
+                if attempt >= config.max_retries {
+                    return Err(e);
+                }
+                let delay = backoff_delay(config.retry_base_delay_ms, attempt);
+                eprintln!(
+                    "Recoverable error (attempt {}/{}), retrying in {delay}ms: {e:?}",
+                    attempt + 1,
+                    config.max_retries
+                );
+                tokio::time::sleep(std::time::Duration::from_millis(delay)).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// A small pool of headless Firefox sessions handed out to concurrent requests.
+///
+/// Sessions are circulated through a bounded channel sized to the pool: `acquire`
+/// waits for a free driver and the returned [`PooledDriver`] guard hands it back
+/// when it is dropped, so up to the pool size of requests scrape at once and
+/// further callers wait until a session is returned.
+struct DriverPool {
+    sender: mpsc::Sender<WebDriver>,
+    receiver: Mutex<mpsc::Receiver<WebDriver>>,
+    /// Clones of every live session, kept so shutdown can `.quit()` them even
+    /// while they are on loan to an in-flight request.
+    sessions: Mutex<Vec<WebDriver>>,
+}
+
+/// A driver borrowed from a [`DriverPool`]. Derefs to the underlying
+/// [`WebDriver`] and returns it to the pool on drop.
+struct PooledDriver {
+    driver: Option<WebDriver>,
+    sender: mpsc::Sender<WebDriver>,
+}
+
+impl DriverPool {
+    /// Spin up `size` headless Firefox sessions and seed the pool with them.
+    async fn new(size: usize) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let (sender, receiver) = mpsc::channel(size);
+        let mut sessions = Vec::with_capacity(size);
+        for _ in 0..size {
+            let driver = init_driver().await?;
+            sessions.push(driver.clone());
+            sender.send(driver).await.expect("pool channel closed");
+        }
+        Ok(Self {
+            sender,
+            receiver: Mutex::new(receiver),
+            sessions: Mutex::new(sessions),
+        })
+    }
+
+    /// Quit every live session, including any currently on loan, so no browser
+    /// is left running. Used for both shutdown and relaunch.
+    async fn quit_all(&self) {
+        // Drop the idle handles first so a returned driver can't be re-lent
+        // after we've quit it.
+        {
+            let mut receiver = self.receiver.lock().await;
+            while receiver.try_recv().is_ok() {}
+        }
+        let mut sessions = self.sessions.lock().await;
+        for driver in sessions.drain(..) {
+            let _ = driver.quit().await;
+        }
+    }
+
+    /// Quit the existing sessions and seed the pool with fresh ones, e.g. after
+    /// geckodriver has been relaunched and the old sessions are dead.
+    async fn refill(&self, size: usize) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.quit_all().await;
+        let mut sessions = self.sessions.lock().await;
+        for _ in 0..size {
+            let driver = init_driver().await?;
+            sessions.push(driver.clone());
+            self.sender.send(driver).await.expect("pool channel closed");
+        }
+        Ok(())
+    }
+
+    /// Wait for a free session and borrow it until the guard is dropped.
+    async fn acquire(&self) -> PooledDriver {
+        let driver = {
+            let mut receiver = self.receiver.lock().await;
+            receiver.recv().await.expect("driver pool channel closed")
+        };
+        PooledDriver {
+            driver: Some(driver),
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+impl std::ops::Deref for PooledDriver {
+    type Target = WebDriver;
+
+    fn deref(&self) -> &WebDriver {
+        self.driver.as_ref().expect("driver already returned to pool")
+    }
+}
+
+impl Drop for PooledDriver {
+    fn drop(&mut self) {
+        if let Some(driver) = self.driver.take() {
+            // The channel is sized to the pool, so there is always room for a
+            // borrowed driver to go back.
+            let _ = self.sender.try_send(driver);
+        }
+    }
+}
 
 trait HandleError<T> {
     fn handle_error(
@@ -59,6 +363,26 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
     // Load the .env file.
     dotenv().ok();
 
+    // Load operator configuration, falling back to the built-in defaults.
+    let mut config = confy::load::<Config>("blog-to-speech", None)?;
+    // Let the allowlist be overridden from the environment for convenience.
+    if let Ok(ids) = std::env::var("ALLOWED_USER_IDS") {
+        config.allowed_user_ids = ids
+            .split(',')
+            .filter_map(|id| id.trim().parse().ok())
+            .collect();
+    }
+    let config = Arc::new(config);
+
+    // An empty allowlist leaves the bot open to anyone who finds it, draining
+    // the OpenAI key — warn loudly so operators don't run that way unknowingly.
+    if config.allowed_user_ids.is_empty() {
+        eprintln!(
+            "WARNING: allowed_user_ids is empty — the bot will respond to ANY Telegram user. \
+             Set `allowed_user_ids` in blog-to-speech.toml or the ALLOWED_USER_IDS env var to restrict access."
+        );
+    }
+
     // Kill any existing geckodriver processes
     println!("Killing existing geckodriver processes if any are running");
     let _ = tokio::process::Command::new("pkill")
@@ -66,37 +390,44 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
         .output()
         .await;
 
-    let geckodriver_path = std::env::var("GECKODRIVER_PATH").unwrap_or("geckodriver".to_string());
-    println!("Running geckodriver ({geckodriver_path})");
-    let child = tokio::process::Command::new(geckodriver_path)
-        .stdout(Stdio::piped())
-        .spawn()
-        .expect("Failed to start geckodriver");
-
-    println!("Waiting for geckodriver to start...");
-    let stdout = child.stdout.expect("Failed to get stdout");
-
-    // Combine stdout and stderr into a single stream.
-    let mut reader = BufReader::new(stdout).lines();
-
-    while let Some(line) = reader.next_line().await? {
-        println!("Received: {line}");
-        if line.contains("Listening") {
-            break;
-        }
+    let geckodriver_path =
+        std::env::var("GECKODRIVER_PATH").unwrap_or_else(|_| config.geckodriver_path.clone());
+    let child = Arc::new(Mutex::new(spawn_geckodriver(&geckodriver_path).await?));
+
+    println!("Initializing driver pool...");
+    let pool = Arc::new(DriverPool::new(config.driver_pool_size).await?);
+
+    // Optionally expose the same pipeline over a local HTTP API, sharing the
+    // pool and config.
+    if config.http_enabled {
+        let state = ApiState {
+            pool: pool.clone(),
+            config: config.clone(),
+        };
+        let addr = config.http_addr.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_http(addr, state).await {
+                eprintln!("HTTP server error: {e}");
+            }
+        });
     }
-    println!("Geckodriver started");
-
-    println!("Initializing driver...");
-    let driver = Arc::new(Mutex::new(init_driver().await?));
 
     // Register one command that responds filters any textual message
-    // Pass the web driver to the command.
+    // Pass the web driver pool to the command.
     let bot = Bot::from_env();
     println!("Starting bot...");
-    teloxide::repl(bot, move |bot: Bot, msg: Message| {
-        let driver = driver.clone();
+    let handler_pool = pool.clone();
+    let handler_config = config.clone();
+    let repl = teloxide::repl(bot, move |bot: Bot, msg: Message| {
+        let pool = handler_pool.clone();
+        let config = handler_config.clone();
         async move {
+            // Gate the expensive pipeline behind the configured allowlist.
+            if !is_authorized(msg.from(), &config) {
+                bot.send_message(msg.chat.id, "Sorry, you are not allowed to use this bot.")
+                    .await?;
+                return Ok(());
+            }
             let url = match msg.text() {
                 Some(text) => text,
                 None => return Ok(()),
@@ -104,60 +435,260 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
             println!("Received URL: {}", url);
             bot.send_message(msg.chat.id, "Got it! Working on it. It may take a while...")
                 .await?;
-            // Navigate to the page.
-            let blog_text = {
-                let driver = driver.lock().await;
-                get_blog_text(&driver, &url).await
-            }
-            .handle_error(
-                bot.clone(),
-                msg.chat.id,
-                "Error retrieving blog text".to_string(),
-            )
-            .await?;
-
-            let len = blog_text.len();
-            println!("Retrieved blog text of length {len}");
-
-            println!("Editing text...");
-            let edited_blog_text = edit_text(&blog_text)
+            // Scrape, edit, and synthesize the post, consuming the parts as they
+            // complete rather than collecting them all up front.
+            let parts = synthesize_stream(url, &pool, &config)
                 .await
-                .handle_error(bot.clone(), msg.chat.id, "Error editing text".to_string())
+                .handle_error(bot.clone(), msg.chat.id, "Error processing post".to_string())
                 .await?;
-
-            // Loop on the text and break it into chunks of at most 4096 characters.
-            // But break on word boundaries.
-            for (i, chunk) in chunk_text_by_lines(&edited_blog_text, 4096)
-                .iter()
-                .enumerate()
-            {
-                println!("Converting part {i} to speech...");
-                let audio_bytes = text_to_speech(&chunk)
-                    .await
-                    .handle_error(
-                        bot.clone(),
-                        msg.chat.id,
-                        "Error converting text to speech".to_string(),
-                    )
-                    .await?;
-
-                println!("Sending audio of part {i}...");
+            tokio::pin!(parts);
+
+            if config.single_file_upload {
+                // Stitch the ordered MP3 parts into one continuous file and
+                // upload it once. Note `InputFile::memory` requires the whole
+                // body up front, so peak memory here scales with the full post;
+                // only the multi-part branch is truly streamed.
+                let silence = match &config.silence_frame_path {
+                    Some(path) => tokio::fs::read(path).await.ok(),
+                    None => None,
+                };
+                let mut body = Vec::new();
+                let mut i = 0;
+                while let Some(part) = parts.next().await {
+                    let part = part
+                        .handle_error(
+                            bot.clone(),
+                            msg.chat.id,
+                            "Error converting text to speech".to_string(),
+                        )
+                        .await?;
+                    if i > 0 {
+                        if let Some(silence) = &silence {
+                            body.extend_from_slice(silence);
+                        }
+                    }
+                    body.extend_from_slice(&part);
+                    i += 1;
+                }
+                println!("Sending single audio file...");
                 bot.send_audio(
                     msg.chat.id,
-                    InputFile::memory(audio_bytes).file_name(format!("part_{i}.mp3")),
+                    InputFile::memory(body).file_name(post_file_name(url)),
                 )
                 .await?;
+            } else {
+                let mut i = 0;
+                while let Some(part) = parts.next().await {
+                    let audio_bytes = part
+                        .handle_error(
+                            bot.clone(),
+                            msg.chat.id,
+                            "Error converting text to speech".to_string(),
+                        )
+                        .await?;
+                    println!("Sending audio of part {i}...");
+                    bot.send_audio(
+                        msg.chat.id,
+                        InputFile::memory(audio_bytes).file_name(format!("part_{i}.mp3")),
+                    )
+                    .await?;
+                    i += 1;
+                }
             }
 
             Ok(())
         }
-    })
-    .await;
+    });
+
+    // Drive the bot until it is interrupted, restarting geckodriver (and
+    // rebuilding the pool) underneath it if the child dies, so a crashed
+    // browser self-heals instead of failing every subsequent URL.
+    tokio::pin!(repl);
+    loop {
+        tokio::select! {
+            _ = &mut repl => break,
+            _ = shutdown_signal() => {
+                println!("Shutdown signal received; cleaning up...");
+                break;
+            }
+            status = wait_for_child(&child) => {
+                eprintln!("geckodriver exited ({status:?}); relaunching...");
+                *child.lock().await = spawn_geckodriver(&geckodriver_path).await?;
+                pool.refill(config.driver_pool_size).await?;
+                println!("geckodriver relaunched and pool rebuilt");
+            }
+        }
+    }
+
+    // Quit the browser sessions and kill the child so no orphans are left.
+    pool.quit_all().await;
+    let _ = child.lock().await.kill().await;
+    println!("Goodbye.");
 
     Ok(())
 }
 
-fn chunk_text_by_lines(text: &str, max_chunk_size: usize) -> Vec<String> {
+/// Scrape + edit + chunk `url`, returning an ordered stream of synthesized MP3
+/// parts. Parts are yielded as the buffered TTS conversions complete (in reading
+/// order), so a consumer can forward them one at a time instead of holding the
+/// whole post in memory.
+async fn synthesize_stream<'a>(
+    url: &str,
+    pool: &DriverPool,
+    config: &'a Config,
+) -> Result<
+    impl Stream<Item = Result<Bytes, Box<dyn Error + Send + Sync>>> + 'a,
+    Box<dyn Error + Send + Sync>,
+> {
+    let blog_text = {
+        let driver = pool.acquire().await;
+        get_blog_text(&driver, url, config).await?
+    };
+
+    let len = blog_text.len();
+    println!("Retrieved blog text of length {len}");
+
+    println!("Editing text...");
+    let edited_blog_text = edit_text(&blog_text, config).await?;
+
+    // Break the text into chunks of up to `max_chunk_chars` on line
+    // boundaries (a single longer line is kept whole as its own chunk), then
+    // convert them to speech in parallel while keeping their original order
+    // for playback.
+    let chunks = chunk_text_by_lines(&edited_blog_text, config);
+    Ok(stream::iter(chunks.into_iter())
+        .map(move |chunk| async move { text_to_speech(&chunk, config).await })
+        .buffered(config.max_tts_concurrency))
+}
+
+/// Collect the whole pipeline into ordered audio parts. Used by the HTTP API,
+/// which needs all the parts to build its single response body.
+async fn run_pipeline(
+    url: &str,
+    pool: &DriverPool,
+    config: &Config,
+) -> Result<Vec<Bytes>, Box<dyn Error + Send + Sync>> {
+    synthesize_stream(url, pool, config)
+        .await?
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect()
+}
+
+/// Shared state handed to the HTTP handlers.
+#[derive(Clone)]
+struct ApiState {
+    pool: Arc<DriverPool>,
+    config: Arc<Config>,
+}
+
+/// Body of a `POST /speech` request.
+#[derive(Deserialize)]
+struct SpeechRequest {
+    url: String,
+}
+
+/// `POST /speech` — run the pipeline for the given URL and send back the
+/// synthesized audio as a single MP3 body, built once every part has been
+/// synthesized. When `http_auth_token` is configured
+/// the caller must present it as `Authorization: Bearer <token>`.
+async fn speech_handler(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Json(request): Json<SpeechRequest>,
+) -> Result<Response, (StatusCode, String)> {
+    if let Some(token) = &state.config.http_auth_token {
+        let provided = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok());
+        if provided != Some(format!("Bearer {token}").as_str()) {
+            return Err((StatusCode::UNAUTHORIZED, "unauthorized".to_string()));
+        }
+    }
+
+    let parts = run_pipeline(&request.url, &state.pool, &state.config)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{e}")))?;
+
+    let mut body = Vec::new();
+    for part in parts {
+        body.extend_from_slice(&part);
+    }
+
+    Ok(([(header::CONTENT_TYPE, "audio/mpeg")], body).into_response())
+}
+
+/// Bind the HTTP API to `addr` and serve requests until the process exits.
+async fn serve_http(addr: String, state: ApiState) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let app = axum::Router::new()
+        .route("/speech", axum::routing::post(speech_handler))
+        .with_state(state.clone());
+    if state.config.http_auth_token.is_none() {
+        eprintln!(
+            "WARNING: HTTP API has no http_auth_token set — POST /speech is unauthenticated. \
+             Only bind http_addr to a loopback address, or set a token."
+        );
+    }
+    let listener = tokio::net::TcpListener::bind(&addr).await?;
+    println!("HTTP API listening on {addr}");
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+/// Spawn `geckodriver` and wait until it reports that it is listening.
+async fn spawn_geckodriver(
+    path: &str,
+) -> Result<tokio::process::Child, Box<dyn Error + Send + Sync>> {
+    println!("Running geckodriver ({path})");
+    let mut child = tokio::process::Command::new(path)
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    println!("Waiting for geckodriver to start...");
+    let stdout = child.stdout.take().expect("Failed to get stdout");
+    let mut reader = BufReader::new(stdout).lines();
+    while let Some(line) = reader.next_line().await? {
+        println!("Received: {line}");
+        if line.contains("Listening") {
+            break;
+        }
+    }
+    println!("Geckodriver started");
+
+    Ok(child)
+}
+
+/// Resolve once the process receives SIGINT (Ctrl-C) or SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = tokio::signal::ctrl_c();
+
+    #[cfg(unix)]
+    {
+        let mut term = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler");
+        tokio::select! {
+            _ = ctrl_c => {}
+            _ = term.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = ctrl_c.await;
+    }
+}
+
+/// Wait for the supervised geckodriver child to exit.
+async fn wait_for_child(
+    child: &Arc<Mutex<tokio::process::Child>>,
+) -> std::io::Result<std::process::ExitStatus> {
+    let mut guard = child.lock().await;
+    guard.wait().await
+}
+
+fn chunk_text_by_lines(text: &str, config: &Config) -> Vec<String> {
+    let max_chunk_size = config.max_chunk_chars;
     let mut chunks = Vec::new();
     let mut current_chunk = String::new();
 
@@ -197,39 +728,53 @@ async fn init_driver() -> Result<WebDriver, Box<dyn Error + Send + Sync>> {
 async fn get_blog_text(
     driver: &thirtyfour::WebDriver,
     blog: &str,
+    config: &Config,
 ) -> Result<String, Box<dyn Error + Send + Sync>> {
-    driver.goto(blog).await?;
+    retry_recoverable(config, || async {
+        driver.goto(blog).await.map_err(classify_webdriver)?;
 
-    let article = driver.find(thirtyfour::By::Tag("article")).await?;
+        let article = driver
+            .find(thirtyfour::By::Tag("article"))
+            .await
+            .map_err(classify_webdriver)?;
 
-    let paragraphs = article.find_all(thirtyfour::By::Tag("p")).await?;
+        let paragraphs = article
+            .find_all(thirtyfour::By::Tag("p"))
+            .await
+            .map_err(classify_webdriver)?;
 
-    let mut text = String::new();
+        let mut text = String::new();
 
-    for p in paragraphs {
-        text.push_str(&p.text().await?);
-        text.push_str("\n");
-    }
+        for p in paragraphs {
+            text.push_str(&p.text().await.map_err(classify_webdriver)?);
+            text.push_str("\n");
+        }
 
-    Ok(text)
+        Ok(text)
+    })
+    .await
 }
 
-async fn edit_text(text: &str) -> Result<String, Box<dyn Error + Send + Sync + 'static>> {
+async fn edit_text(
+    text: &str,
+    config: &Config,
+) -> Result<String, Box<dyn Error + Send + Sync + 'static>> {
     let bearer_token = std::env::var("OPENAI_BEARER_TOKEN")?;
     let client = reqwest::Client::new();
+    retry_recoverable(config, || async {
     let response = client
         .post("https://api.openai.com/v1/chat/completions")
         .header("Authorization", format!("Bearer {}", bearer_token))
         .header("Content-Type", "application/json")
         .json(&serde_json::json!({
-            "model": "gpt-4o",
+            "model": config.edit_model,
             "messages": [
                 {
                     "role": "user",
                     "content": [
                         {
                             "type": "text",
-                            "text": "Given text from a blog post:\n- Remove any introductory statement or metadata\n- Redact code blocks and replace them with a short technical explanation of their content. Start with \"EDIT:\". End with \"END OF EDIT.\".\nEmojis or other characters that cannot be pronounced should be removed.\nYour response will be directly read of the user - so avoid any additional content besides the edited post\n\nOK?"
+                            "text": config.edit_prompt
                         }
                     ]
                 },
@@ -256,54 +801,64 @@ async fn edit_text(text: &str) -> Result<String, Box<dyn Error + Send + Sync + '
             "top_p": 1,
             "frequency_penalty": 0,
             "presence_penalty": 0
-        })).send().await?;
+        })).send().await.map_err(classify_reqwest)?;
     let status = response.status();
     if !status.is_success() {
-        let text = response.text().await?;
-        return Err(Box::new(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!("Failed to edit text: {status}: {text}"),
-        )));
+        let body = response.text().await.map_err(classify_reqwest)?;
+        return Err(classify_status(
+            status,
+            format!("Failed to edit text: {status}: {body}"),
+        ));
     }
 
-    let text = response.json::<serde_json::Value>().await?;
-    let text = text["choices"][0]["message"]["content"].as_str().unwrap();
-    Ok(text.to_string())
+    let value = response
+        .json::<serde_json::Value>()
+        .await
+        .map_err(classify_reqwest)?;
+    let content = value["choices"][0]["message"]["content"]
+        .as_str()
+        .ok_or_else(|| {
+            // A 200 with an unexpected shape (error object, schema change) is a
+            // server-side problem, so treat it as recoverable and retry.
+            PipelineError::Recoverable(Box::new(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("Unexpected edit response shape: {value}"),
+            )))
+        })?;
+    Ok(content.to_string())
+    })
+    .await
 }
 
-async fn text_to_speech(text: &str) -> Result<Bytes, Box<dyn Error + Send + Sync>> {
+async fn text_to_speech(
+    text: &str,
+    config: &Config,
+) -> Result<Bytes, Box<dyn Error + Send + Sync>> {
     let bearer_token = std::env::var("OPENAI_BEARER_TOKEN")?;
     let client = reqwest::Client::new();
-    let response = client
-        .post("https://api.openai.com/v1/audio/speech")
-        .header("Authorization", format!("Bearer {}", bearer_token))
-        .header("Content-Type", "application/json")
-        .json(&serde_json::json!({
-            "model": "tts-1",
-            "input": text,
-            "voice": "nova"
-        }))
-        .send()
-        .await?;
-    let status = response.status();
-    if !status.is_success() {
-        let text = response.text().await?;
-        return Err(Box::new(std::io::Error::new(
-            std::io::ErrorKind::Other,
-            format!("Failed to convert text to speech: {status}: {text}"),
-        )));
-    }
-
-    let audio_bytes_result = response.bytes().await;
-    let audio_bytes = match audio_bytes_result {
-        Ok(audio_bytes) => audio_bytes,
-        Err(e) => {
-            return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("Failed to read audio bytes: {e}"),
-            )));
+    retry_recoverable(config, || async {
+        let response = client
+            .post("https://api.openai.com/v1/audio/speech")
+            .header("Authorization", format!("Bearer {}", bearer_token))
+            .header("Content-Type", "application/json")
+            .json(&serde_json::json!({
+                "model": config.tts_model,
+                "input": text,
+                "voice": config.voice
+            }))
+            .send()
+            .await
+            .map_err(classify_reqwest)?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.map_err(classify_reqwest)?;
+            return Err(classify_status(
+                status,
+                format!("Failed to convert text to speech: {status}: {body}"),
+            ));
         }
-    };
 
-    Ok(audio_bytes)
+        response.bytes().await.map_err(classify_reqwest)
+    })
+    .await
 }